@@ -1,6 +1,11 @@
 extern crate chrono;
 extern crate sha2;
 extern crate byteorder;
+extern crate reqwest;
+extern crate ed25519_dalek;
+extern crate hex;
+#[macro_use]
+extern crate rusqlite;
 #[macro_use]
 extern crate iron;
 extern crate router;
@@ -11,24 +16,42 @@ extern crate bodyparser;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
+#[cfg(test)]
+extern crate rand;
 
 use std::mem;
 use std::env;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::ops::Deref;
 use chrono::prelude::*;
 use sha2::{Sha256, Digest};
 use byteorder::{BigEndian, WriteBytesExt};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use rusqlite::Connection;
 use iron::prelude::*;
 use iron::status;
 use router::Router;
 use logger::Logger;
 use iron::typemap::Key;
-use persistent::State;
+use persistent::{Read, State};
 use iron::mime::Mime;
 
+// A run of raw bytes, e.g. a block hash or a serialized block.
+type Bytes = Vec<u8>;
+
+// Default number of leading zero bits a valid proof-of-work digest must have.
+// Overridable per-process with the `DIFFICULTY` environment variable.
+const DIFFICULTY: usize = 16;
+
+// Number of background threads that verify imported blocks off the request path.
+const VERIFIER_THREADS: usize = 4;
+
+// Per-request timeout, in seconds, when fetching a peer's chain during sync.
+const PEER_TIMEOUT_SECS: u64 = 5;
+
 fn main() {
 
     let mut router = Router::new();
@@ -37,12 +60,18 @@ fn main() {
     router.get("/mine", mine, "mine");
     router.post("/transactions/new", transactions_new, "transactions_new");
     router.get("/chain", chain, "chain");
+    router.post("/nodes/register", nodes_register, "nodes_register");
+    router.get("/nodes/resolve", nodes_resolve, "nodes_resolve");
 
     let mut c = Chain::new(router);
     let (logger_before, logger_after) = Logger::new(None);
     c.link_before(logger_before);
     c.link_after(logger_after);
-    c.link(State::<Blockchain>::both(RwLock::new(new_blockchain())));
+    c.link(State::<Blockchain>::both(RwLock::new(load_blockchain("blockchain.db"))));
+
+    let queue = BlockQueue::new();
+    queue.start(VERIFIER_THREADS);
+    c.link(Read::<BlockQueueKey>::one(queue));
 
     let port = env::var("PORT").unwrap_or("3000".to_owned());
     let addr = format!("localhost:{}", port);
@@ -58,8 +87,9 @@ fn main() {
     fn mine(req: &mut Request) -> IronResult<Response> {
         let arc_rw_lock = req.get::<State<Blockchain>>().unwrap();
         let mut bc = arc_rw_lock.write().unwrap();
-        let proof = Blockchain::proof_of_work(bc.last_block().proof);
-        bc.new_block(proof, None);
+        let difficulty = Blockchain::difficulty();
+        let proof = Blockchain::proof_of_work(bc.last_block().proof, difficulty);
+        bc.new_block(proof, None, difficulty);
 
         let content_type = "application/json".parse::<Mime>().unwrap();
         let resp = json!({"block":bc.last_block()});
@@ -73,11 +103,18 @@ fn main() {
         let arc_rw_lock = req.get::<State<Blockchain>>().unwrap();
         let mut bc = arc_rw_lock.write().unwrap();
 
-        // TODO: Provide better error responses here.
         let transaction = iexpect!(itry!(req.get::<bodyparser::Struct<Transaction>>()));
-        bc.new_transaction(transaction);
 
         let content_type = "application/json".parse::<Mime>().unwrap();
+        if let Err(err) = bc.new_transaction(transaction) {
+            let resp = json!({"error": err});
+            return Ok(Response::with((
+                content_type,
+                status::BadRequest,
+                serde_json::to_string(&resp).unwrap(),
+            )));
+        }
+
         let resp = json!({"current_transactions": bc.current_transactions});
         Ok(Response::with((
             content_type,
@@ -97,19 +134,98 @@ fn main() {
             serde_json::to_string(&resp).unwrap(),
         )))
     }
+    fn nodes_register(req: &mut Request) -> IronResult<Response> {
+        let arc_rw_lock = req.get::<State<Blockchain>>().unwrap();
+        let mut bc = arc_rw_lock.write().unwrap();
+
+        let nodes = iexpect!(itry!(req.get::<bodyparser::Struct<Vec<String>>>()));
+        for node in nodes {
+            bc.register_node(node);
+        }
+
+        let content_type = "application/json".parse::<Mime>().unwrap();
+        let resp = json!({"total_nodes": bc.nodes});
+        Ok(Response::with((
+            content_type,
+            status::Ok,
+            serde_json::to_string(&resp).unwrap(),
+        )))
+    }
+    fn nodes_resolve(req: &mut Request) -> IronResult<Response> {
+        let arc_rw_lock = req.get::<State<Blockchain>>().unwrap();
+        let queue = req.get::<Read<BlockQueueKey>>().unwrap();
+
+        // Snapshot the peer set and tip length under the lock, then release it
+        // before any network I/O so a slow or unreachable peer can't freeze
+        // writers (mine, transactions_new, the committer) while we wait.
+        let (nodes, current_len, genesis_hash) = {
+            let bc = arc_rw_lock.read().unwrap();
+            (bc.nodes.clone(), bc.chain.len(), Blockchain::hash(&bc.chain[0]))
+        };
+
+        // Pick the longest valid peer chain. A chain that simply extends our
+        // tip is handed to the verification queue so its new blocks are checked
+        // and committed off this thread; a strictly-longer *forked* chain is
+        // validated and adopted in place, mirroring the chunk0-1 contract.
+        let candidate = Blockchain::find_longer_chain(&nodes, current_len, &genesis_hash);
+
+        let content_type = "application/json".parse::<Mime>().unwrap();
+        let resp = match candidate {
+            Some(chain) => {
+                let is_extension = {
+                    let bc = arc_rw_lock.read().unwrap();
+                    Blockchain::is_extension(&bc.chain, &chain)
+                };
+                if is_extension {
+                    let items = {
+                        let bc = arc_rw_lock.read().unwrap();
+                        Blockchain::blocks_to_import(&bc.chain, chain)
+                    };
+                    let count = items.len();
+                    queue.import(arc_rw_lock.clone(), items);
+                    json!({"message": "Sync scheduled", "queued_blocks": count})
+                } else {
+                    let mut bc = arc_rw_lock.write().unwrap();
+                    bc.replace_chain(chain);
+                    json!({"message": "Our chain was replaced", "chain": bc.chain})
+                }
+            }
+            None => json!({"message": "Our chain is authoritative", "queued_blocks": 0}),
+        };
+        Ok(Response::with((
+            content_type,
+            status::Ok,
+            serde_json::to_string(&resp).unwrap(),
+        )))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Blockchain {
     chain: Vec<Block>,
     current_transactions: Vec<Transaction>,
+    nodes: HashSet<String>,
+    #[serde(skip)]
+    db: Option<Mutex<Connection>>,
 }
 
-// Create an initialized blockchain.
+// Create an initialized, in-memory blockchain with a fresh genesis block.
 fn new_blockchain() -> Blockchain {
     let mut bc = Blockchain { ..Default::default() };
     // add genesis block
-    bc.new_block(100, Some(1));
+    bc.new_block(100, Some(vec![0; 32]), Blockchain::difficulty());
+    bc
+}
+
+// Opens (creating if needed) the on-disk database at `path`, resumes from the
+// persisted tip, or lays down a fresh genesis block when the store is empty.
+fn load_blockchain(path: &str) -> Blockchain {
+    let mut bc = Blockchain { ..Default::default() };
+    bc.init_db(path);
+    bc.load();
+    if bc.chain.is_empty() {
+        bc.new_block(100, Some(vec![0; 32]), Blockchain::difficulty());
+    }
     bc
 }
 
@@ -118,6 +234,8 @@ impl Default for Blockchain {
         Blockchain {
             chain: Vec::new(),
             current_transactions: Vec::new(),
+            nodes: HashSet::new(),
+            db: None,
         }
     }
 }
@@ -128,7 +246,7 @@ impl Key for Blockchain {
 
 impl Blockchain {
     // Creates a new Block and adds it to the chain
-    fn new_block(&mut self, proof: u64, previous_hash: Option<u64>) {
+    fn new_block(&mut self, proof: u64, previous_hash: Option<Bytes>, difficulty: usize) {
         let previous_hash = previous_hash.unwrap_or_else(|| Blockchain::hash(self.last_block()));
 
         let mut previous_transactions = Vec::new();
@@ -140,22 +258,30 @@ impl Blockchain {
             transactions: previous_transactions,
             proof: proof,
             previous_hash: previous_hash,
+            difficulty: difficulty,
         };
 
         self.chain.push(block);
+        let block = self.chain.last().unwrap();
+        self.add_block(block);
     }
 
-    // Adds a new transaction to the list of transactions
-    fn new_transaction(&mut self, transaction: Transaction) -> usize {
+    // Adds a new transaction to the list of transactions after verifying its
+    // signature. Returns the index of the block that will hold it, or an error
+    // describing why the transaction was rejected.
+    fn new_transaction(&mut self, transaction: Transaction) -> Result<usize, String> {
+        transaction.verify()?;
         self.current_transactions.push(transaction);
-        self.last_block().index + 1
+        Ok(self.last_block().index + 1)
     }
 
-    // Hashes a Block
-    fn hash(block: &Block) -> u64 {
-        let mut s = DefaultHasher::new();
-        block.hash(&mut s);
-        s.finish()
+    // Hashes a Block with SHA-256 over its canonical byte serialization, so
+    // that `previous_hash` linkage is stable across nodes, platforms and Rust
+    // versions.
+    fn hash(block: &Block) -> Bytes {
+        let mut hasher = Sha256::default();
+        hasher.input(&block.as_bytes());
+        hasher.result().to_vec()
     }
 
     // Returns the last Block in the chain
@@ -163,42 +289,551 @@ impl Blockchain {
         &self.chain[self.chain.len() - 1]
     }
 
-    fn proof_of_work(last_proof: u64) -> u64 {
+    // Reads the configured mining difficulty from the `DIFFICULTY` environment
+    // variable, falling back to the `DIFFICULTY` constant.
+    fn difficulty() -> usize {
+        env::var("DIFFICULTY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DIFFICULTY)
+    }
+
+    fn proof_of_work(last_proof: u64, difficulty: usize) -> u64 {
         let mut proof: u64 = 0;
-        while Blockchain::valid_proof(last_proof, proof) == false {
+        while Blockchain::valid_proof(last_proof, proof, difficulty) == false {
             proof += 1;
         }
         proof
     }
-    fn valid_proof(last_proof: u64, proof: u64) -> bool {
+    // A proof is valid when SHA-256(last_proof || proof) begins with at least
+    // `difficulty` leading zero bits.
+    fn valid_proof(last_proof: u64, proof: u64, difficulty: usize) -> bool {
         let mut wtr = vec![];
         wtr.write_u64::<BigEndian>(last_proof).unwrap();
         wtr.write_u64::<BigEndian>(proof).unwrap();
         let mut hasher = Sha256::default();
         hasher.input(&wtr[..]);
-        hasher.result()[..2] == b"00"[..2]
+        let digest = hasher.result();
+
+        let mut zeros = 0;
+        for byte in digest.iter() {
+            zeros += byte.leading_zeros() as usize;
+            if *byte != 0 {
+                break;
+            }
+        }
+        zeros >= difficulty
+    }
+
+    // Registers a peer node by its base URL (e.g. "http://localhost:3001")
+    fn register_node(&mut self, address: String) {
+        self.nodes.insert(address);
+    }
+
+    // Determines if a given chain is valid. `expected_genesis` is the hash of
+    // our own first block, which the candidate's block index 1 must match so a
+    // chain built on a different genesis can never be adopted.
+    fn valid_chain(chain: &[Block], expected_genesis: &Bytes) -> bool {
+        match chain.first() {
+            Some(first) if &Blockchain::hash(first) == expected_genesis => {}
+            _ => return false,
+        }
+        for block in chain {
+            if !block.transactions_valid() {
+                return false;
+            }
+        }
+        for window in chain.windows(2) {
+            let prev = &window[0];
+            let block = &window[1];
+            if block.previous_hash != Blockchain::hash(prev) {
+                return false;
+            }
+            if !Blockchain::valid_proof(prev.proof, block.proof, block.difficulty) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Polls every registered peer for its chain and returns the longest one
+    // that is strictly longer than `current_len` and passes `valid_chain`.
+    //
+    // Takes a snapshot of the peer set rather than `&self` so the caller can
+    // drop the `Blockchain` lock before this blocking network I/O runs; every
+    // request carries an explicit timeout so one dead peer can't stall sync.
+    fn find_longer_chain(
+        nodes: &HashSet<String>,
+        current_len: usize,
+        expected_genesis: &Bytes,
+    ) -> Option<Vec<Block>> {
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_secs(PEER_TIMEOUT_SECS))
+            .build()
+        {
+            Ok(client) => client,
+            Err(_) => return None,
+        };
+
+        let mut new_chain: Option<Vec<Block>> = None;
+        let mut max_length = current_len;
+
+        for node in nodes {
+            let url = format!("{}/chain", node);
+            let mut response = match client.get(&url).send() {
+                Ok(response) => response,
+                Err(_) => continue,
+            };
+            let body: serde_json::Value = match response.json() {
+                Ok(body) => body,
+                Err(_) => continue,
+            };
+            let chain: Vec<Block> = match serde_json::from_value(body["chain"]["chain"].clone()) {
+                Ok(chain) => chain,
+                Err(_) => continue,
+            };
+
+            if chain.len() > max_length && Blockchain::valid_chain(&chain, expected_genesis) {
+                max_length = chain.len();
+                new_chain = Some(chain);
+            }
+        }
+
+        new_chain
+    }
+
+    // Returns true when `candidate` shares `current`'s full prefix, i.e. it only
+    // adds blocks on top of our current tip rather than replacing it.
+    fn is_extension(current: &[Block], candidate: &[Block]) -> bool {
+        if candidate.len() < current.len() {
+            return false;
+        }
+        match current.last() {
+            Some(tip) => Blockchain::hash(tip) == Blockchain::hash(&candidate[current.len() - 1]),
+            None => true,
+        }
+    }
+
+    // Adopts a strictly-longer, valid forked chain wholesale, re-persisting it so
+    // the on-disk store matches the new tip after a restart.
+    fn replace_chain(&mut self, chain: Vec<Block>) {
+        let expected_genesis = Blockchain::hash(&self.chain[0]);
+        if chain.len() <= self.chain.len() || !Blockchain::valid_chain(&chain, &expected_genesis) {
+            return;
+        }
+        self.chain = chain;
+        if let Some(ref db) = self.db {
+            let conn = db.lock().unwrap();
+            conn.execute_batch("DELETE FROM transactions; DELETE FROM blocks;")
+                .unwrap();
+        }
+        for index in 0..self.chain.len() {
+            let block = &self.chain[index];
+            self.add_block(block);
+        }
+    }
+
+    // Splits `candidate` into the blocks that extend `current` past its tip,
+    // pairing each with the block it links onto so it can be verified in
+    // isolation on a background thread.
+    fn blocks_to_import(current: &[Block], candidate: Vec<Block>) -> Vec<Unverified> {
+        let start = current.len();
+        let mut items = Vec::new();
+        for index in start..candidate.len() {
+            items.push(Unverified {
+                previous: candidate[index - 1].clone(),
+                block: candidate[index].clone(),
+            });
+        }
+        items
+    }
+
+    // Appends an already-formed block to the chain and persists it.
+    fn append_block(&mut self, block: Block) {
+        self.chain.push(block);
+        let block = self.chain.last().unwrap();
+        self.add_block(block);
+    }
+
+    // Opens the SQLite store at `path`, creating the schema if absent.
+    fn init_db(&mut self, path: &str) {
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS blocks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                'index' INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                proof INTEGER NOT NULL,
+                difficulty INTEGER NOT NULL,
+                previous_hash BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS transactions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                block INTEGER NOT NULL,
+                sender TEXT NOT NULL,
+                recipient TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                public_key BLOB NOT NULL,
+                signature BLOB NOT NULL
+            );",
+        ).unwrap();
+        self.db = Some(Mutex::new(conn));
+    }
+
+    // Loads every persisted block, in index order, into `chain`.
+    fn load(&mut self) {
+        let rows = match self.db {
+            Some(ref db) => {
+                let conn = db.lock().unwrap();
+                let mut stmt = conn
+                    .prepare("SELECT id, \"index\", timestamp, proof, difficulty, previous_hash \
+                              FROM blocks ORDER BY \"index\" ASC")
+                    .unwrap();
+                let mapped = stmt
+                    .query_map(params![], |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            Block {
+                                index: row.get::<_, i64>(1)? as usize,
+                                timestamp: Utc.timestamp(row.get::<_, i64>(2)?, 0),
+                                transactions: Vec::new(),
+                                proof: row.get::<_, i64>(3)? as u64,
+                                difficulty: row.get::<_, i64>(4)? as usize,
+                                previous_hash: row.get::<_, Vec<u8>>(5)?,
+                            },
+                        ))
+                    })
+                    .unwrap()
+                    .map(|r| r.unwrap())
+                    .collect::<Vec<_>>();
+                let mut chain = Vec::new();
+                for (id, mut block) in mapped {
+                    let mut tstmt = conn
+                        .prepare("SELECT sender, recipient, amount, public_key, signature \
+                                  FROM transactions WHERE block = ?1 ORDER BY id ASC")
+                        .unwrap();
+                    block.transactions = tstmt
+                        .query_map(params![id], |row| {
+                            Ok(Transaction {
+                                sender: row.get(0)?,
+                                recipient: row.get(1)?,
+                                amount: row.get(2)?,
+                                public_key: row.get(3)?,
+                                signature: row.get(4)?,
+                            })
+                        })
+                        .unwrap()
+                        .map(|t| t.unwrap())
+                        .collect();
+                    chain.push(block);
+                }
+                chain
+            }
+            None => return,
+        };
+        self.chain = rows;
+    }
+
+    // Appends `block` and its transactions to the SQLite store. A no-op when no
+    // database is attached (e.g. an in-memory chain used in tests).
+    fn add_block(&self, block: &Block) {
+        if let Some(ref db) = self.db {
+            let conn = db.lock().unwrap();
+            conn.execute(
+                "INSERT INTO blocks (\"index\", timestamp, proof, difficulty, previous_hash) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    block.index as i64,
+                    block.timestamp.timestamp(),
+                    block.proof as i64,
+                    block.difficulty as i64,
+                    block.previous_hash,
+                ],
+            ).unwrap();
+            let block_id = conn.last_insert_rowid();
+            for transaction in &block.transactions {
+                conn.execute(
+                    "INSERT INTO transactions (block, sender, recipient, amount, public_key, signature) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        block_id,
+                        transaction.sender,
+                        transaction.recipient,
+                        transaction.amount,
+                        transaction.public_key,
+                        transaction.signature,
+                    ],
+                ).unwrap();
+            }
+        }
     }
 }
 
-#[derive(Hash, Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Block {
     index: usize,
     timestamp: DateTime<Utc>,
     transactions: Vec<Transaction>,
     proof: u64,
-    previous_hash: u64,
+    previous_hash: Bytes,
+    difficulty: usize,
+}
+
+// Appends a length-prefixed field: a big-endian u64 byte count followed by the
+// bytes themselves, so adjacent variable-length fields can't be confused (e.g.
+// ("ab", "c") and ("a", "bc") must not serialize identically).
+fn write_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.write_u64::<BigEndian>(field.len() as u64).unwrap();
+    bytes.extend_from_slice(field);
 }
 
-#[derive(Hash, Debug, Clone, Serialize, Deserialize)]
+impl Block {
+    // Serializes the block into a fixed big-endian byte layout suitable for
+    // hashing: index, timestamp, proof, difficulty, previous_hash, then each
+    // transaction.
+    fn as_bytes(&self) -> Bytes {
+        let mut bytes = Vec::new();
+        bytes.write_u64::<BigEndian>(self.index as u64).unwrap();
+        bytes.write_i64::<BigEndian>(self.timestamp.timestamp()).unwrap();
+        bytes.write_u64::<BigEndian>(self.proof).unwrap();
+        bytes.write_u64::<BigEndian>(self.difficulty as u64).unwrap();
+        bytes.extend_from_slice(&self.previous_hash);
+        for transaction in &self.transactions {
+            write_field(&mut bytes, transaction.sender.as_bytes());
+            write_field(&mut bytes, transaction.recipient.as_bytes());
+            bytes.write_i64::<BigEndian>(transaction.amount).unwrap();
+        }
+        bytes
+    }
+
+    // True when every transaction the block carries has a valid signature.
+    fn transactions_valid(&self) -> bool {
+        self.transactions.iter().all(|t| t.verify().is_ok())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Transaction {
     sender: String,
     recipient: String,
     amount: i64,
+    public_key: Bytes,
+    signature: Bytes,
+}
+
+impl Transaction {
+    // The signed payload: the transfer described by (sender, recipient, amount)
+    // in the same fixed big-endian layout used elsewhere.
+    fn payload(&self) -> Bytes {
+        let mut bytes = Vec::new();
+        write_field(&mut bytes, self.sender.as_bytes());
+        write_field(&mut bytes, self.recipient.as_bytes());
+        bytes.write_i64::<BigEndian>(self.amount).unwrap();
+        bytes
+    }
+
+    // Verifies that `signature` is a valid signature over `payload()` under
+    // `public_key`, and that `sender` is the SHA-256 hash of that public key.
+    fn verify(&self) -> Result<(), String> {
+        let public_key = PublicKey::from_bytes(&self.public_key)
+            .map_err(|e| format!("invalid public key: {}", e))?;
+        let signature = Signature::from_bytes(&self.signature)
+            .map_err(|e| format!("invalid signature: {}", e))?;
+
+        let mut hasher = Sha256::default();
+        hasher.input(&self.public_key);
+        let expected_sender = hex::encode(hasher.result());
+        if self.sender != expected_sender {
+            return Err("sender does not match public key".to_owned());
+        }
+
+        public_key
+            .verify(&self.payload(), &signature)
+            .map_err(|e| format!("signature verification failed: {}", e))
+    }
+}
+
+// A block awaiting verification, paired with the block it claims to extend so
+// that its proof and `previous_hash` can be checked in isolation.
+struct Unverified {
+    previous: Block,
+    block: Block,
+}
+
+// A concurrent verification subsystem: background threads drain `unverified`,
+// push passing blocks to `verified` and record the hashes of rejected blocks in
+// `bad` to short-circuit their re-submission. A single committer thread then
+// drains `verified` in index order into the `Blockchain`.
+//
+// The three collections each sit behind their own `Mutex`; when more than one
+// is held at a time the locks are always acquired in the declared field order
+// (`unverified`, then `verified`, then `bad`) to avoid deadlock.
+struct BlockQueue {
+    unverified: Mutex<VecDeque<Unverified>>,
+    verified: Mutex<Vec<Block>>,
+    bad: Mutex<HashSet<String>>,
+    more_to_verify: Condvar,
+    chain: Mutex<Option<Arc<RwLock<Blockchain>>>>,
+}
+
+impl BlockQueue {
+    fn new() -> Arc<BlockQueue> {
+        Arc::new(BlockQueue {
+            unverified: Mutex::new(VecDeque::new()),
+            verified: Mutex::new(Vec::new()),
+            bad: Mutex::new(HashSet::new()),
+            more_to_verify: Condvar::new(),
+            chain: Mutex::new(None),
+        })
+    }
+
+    // Spawns `verifiers` verification threads plus one committer thread.
+    fn start(self: &Arc<Self>, verifiers: usize) {
+        for _ in 0..verifiers {
+            let queue = Arc::clone(self);
+            thread::spawn(move || queue.verify_loop());
+        }
+        let queue = Arc::clone(self);
+        thread::spawn(move || queue.commit_loop());
+    }
+
+    // Enqueues candidate blocks for verification, skipping any already known to
+    // be bad, and records the chain handle the committer will write into.
+    fn import(&self, chain: Arc<RwLock<Blockchain>>, blocks: Vec<Unverified>) {
+        {
+            let mut handle = self.chain.lock().unwrap();
+            if handle.is_none() {
+                *handle = Some(chain);
+            }
+        }
+
+        let mut unverified = self.unverified.lock().unwrap();
+        let bad = self.bad.lock().unwrap();
+        for item in blocks {
+            let hash = hex::encode(Blockchain::hash(&item.block));
+            if bad.contains(&hash) {
+                continue;
+            }
+            unverified.push_back(item);
+        }
+        drop(bad);
+        drop(unverified);
+        self.more_to_verify.notify_all();
+    }
+
+    fn verify_loop(&self) {
+        loop {
+            let item = {
+                let mut unverified = self.unverified.lock().unwrap();
+                while unverified.is_empty() {
+                    unverified = self.more_to_verify.wait(unverified).unwrap();
+                }
+                unverified.pop_front().unwrap()
+            };
+
+            let valid = item.block.previous_hash == Blockchain::hash(&item.previous)
+                && Blockchain::valid_proof(
+                    item.previous.proof,
+                    item.block.proof,
+                    item.block.difficulty,
+                )
+                && item.block.transactions_valid();
+
+            if valid {
+                self.verified.lock().unwrap().push(item.block);
+            } else {
+                let hash = hex::encode(Blockchain::hash(&item.block));
+                self.bad.lock().unwrap().insert(hash);
+            }
+        }
+    }
+
+    fn commit_loop(&self) {
+        // Verified blocks arrive out of index order because the verifier threads
+        // run concurrently, so we buffer them here and only append once the tip
+        // catches up. Blocks that are not yet appendable stay in the buffer for
+        // a later pass rather than being dropped.
+        let mut buffer: Vec<Block> = Vec::new();
+        loop {
+            {
+                let mut verified = self.verified.lock().unwrap();
+                buffer.append(&mut *verified);
+            }
+
+            if buffer.is_empty() {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+
+            if let Some(ref chain) = *self.chain.lock().unwrap() {
+                let mut bc = chain.write().unwrap();
+                BlockQueue::commit_ready(&mut buffer, &mut *bc);
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    // Drains every buffered block that contiguously extends `bc`'s tip into the
+    // chain, in index order, leaving non-appendable blocks in `buffer` for a
+    // later pass. Returns the number of blocks committed.
+    fn commit_ready(buffer: &mut Vec<Block>, bc: &mut Blockchain) -> usize {
+        buffer.sort_by_key(|block| block.index);
+
+        let mut committed = 0;
+        loop {
+            let tip_index = bc.last_block().index;
+            // Discard blocks we have already committed from another peer.
+            buffer.retain(|block| block.index > tip_index);
+
+            let next = buffer.iter().position(|block| {
+                block.index == tip_index + 1
+                    && block.previous_hash == Blockchain::hash(bc.last_block())
+            });
+            match next {
+                Some(pos) => {
+                    bc.append_block(buffer.remove(pos));
+                    committed += 1;
+                }
+                None => break,
+            }
+        }
+        committed
+    }
+}
+
+struct BlockQueueKey;
+
+impl Key for BlockQueueKey {
+    type Value = Arc<BlockQueue>;
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ed25519_dalek::{Keypair, Signer};
+
+    // Builds a transaction signed by a freshly generated keypair, with the
+    // sender set to the hash of the public key so that `verify` accepts it.
+    fn signed_transaction(recipient: &str, amount: i64) -> Transaction {
+        let mut csprng = rand::rngs::OsRng {};
+        let keypair = Keypair::generate(&mut csprng);
+        let public_key = keypair.public.to_bytes().to_vec();
+
+        let mut hasher = Sha256::default();
+        hasher.input(&public_key);
+        let sender = hex::encode(hasher.result());
+
+        let mut transaction = Transaction {
+            sender,
+            recipient: recipient.to_owned(),
+            amount,
+            public_key,
+            signature: Vec::new(),
+        };
+        transaction.signature = keypair.sign(&transaction.payload()).to_bytes().to_vec();
+        transaction
+    }
 
     #[test]
     fn it_works() {
@@ -206,20 +841,112 @@ mod tests {
         assert_eq!(bc.chain.len(), 1);
 
         // new block
-        bc.new_transaction(Transaction {
-            sender: "me".to_owned(),
-            recipient: "you".to_owned(),
-            amount: 5,
-        });
-        bc.new_transaction(Transaction {
-            sender: "you".to_owned(),
-            recipient: "me".to_owned(),
-            amount: 2,
-        });
+        bc.new_transaction(signed_transaction("you", 5)).unwrap();
+        bc.new_transaction(signed_transaction("me", 2)).unwrap();
         assert_eq!(bc.current_transactions.len(), 2);
 
-        let proof = Blockchain::proof_of_work(bc.last_block().proof);
-        bc.new_block(proof, None);
+        let difficulty = Blockchain::difficulty();
+        let proof = Blockchain::proof_of_work(bc.last_block().proof, difficulty);
+        bc.new_block(proof, None, difficulty);
         assert_eq!(bc.chain.len(), 2);
     }
+
+    #[test]
+    fn rejects_forged_transactions() {
+        let mut bc = new_blockchain();
+
+        // Tampering with the amount after signing invalidates the signature.
+        let mut forged = signed_transaction("you", 5);
+        forged.amount = 5000;
+        assert!(bc.new_transaction(forged).is_err());
+        assert_eq!(bc.current_transactions.len(), 0);
+    }
+
+    // A fresh, unique path under the temp dir for a test-owned SQLite database.
+    fn temp_db_path(tag: &str) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rockchain-test-{}-{}.db", tag, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    // Builds a block whose index and `previous_hash` extend `previous`, with a
+    // fixed timestamp so its hash is deterministic across the test. Its proof is
+    // not mined, so it is only fit for checks that ignore proof-of-work.
+    fn next_block(previous: &Block) -> Block {
+        Block {
+            index: previous.index + 1,
+            timestamp: Utc.timestamp(previous.timestamp.timestamp() + 1, 0),
+            transactions: Vec::new(),
+            proof: 0,
+            difficulty: DIFFICULTY,
+            previous_hash: Blockchain::hash(previous),
+        }
+    }
+
+    #[test]
+    fn persists_across_reload() {
+        let path = temp_db_path("roundtrip");
+        let _ = std::fs::remove_file(&path);
+
+        // Mine a block on a fresh, persisted chain, then capture the tip hashes.
+        let hashes: Vec<Bytes> = {
+            let mut bc = load_blockchain(&path);
+            bc.new_transaction(signed_transaction("you", 7)).unwrap();
+            let difficulty = Blockchain::difficulty();
+            let proof = Blockchain::proof_of_work(bc.last_block().proof, difficulty);
+            bc.new_block(proof, None, difficulty);
+            assert_eq!(bc.chain.len(), 2);
+            bc.chain.iter().map(|b| Blockchain::hash(b)).collect()
+        };
+
+        // A brand new handle pointed at the same file must resume from the tip.
+        let reloaded = load_blockchain(&path);
+        let reloaded_hashes: Vec<Bytes> =
+            reloaded.chain.iter().map(|b| Blockchain::hash(b)).collect();
+        assert_eq!(reloaded.chain.len(), 2);
+        assert_eq!(reloaded_hashes, hashes);
+        assert_eq!(reloaded.chain[1].transactions.len(), 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn committer_commits_out_of_order_blocks_in_order() {
+        let mut bc = new_blockchain();
+        let genesis = bc.chain[0].clone();
+        let b2 = next_block(&genesis);
+        let b3 = next_block(&b2);
+        let b4 = next_block(&b3);
+
+        // Blocks arrive out of index order, as the concurrent verifiers produce
+        // them. All three must end up committed after genesis in strict index
+        // order.
+        let mut buffer = vec![b4.clone(), b2.clone(), b3.clone()];
+        let committed = BlockQueue::commit_ready(&mut buffer, &mut bc);
+        assert_eq!(committed, 3);
+        assert!(buffer.is_empty());
+        let indices: Vec<usize> = bc.chain.iter().map(|b| b.index).collect();
+        assert_eq!(indices, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn committer_buffers_blocks_until_gap_is_filled() {
+        let mut bc = new_blockchain();
+        let genesis = bc.chain[0].clone();
+        let b2 = next_block(&genesis);
+        let b3 = next_block(&b2);
+
+        // b3 turns up before its predecessor: nothing commits, and b3 is kept
+        // rather than dropped (the regression the commit-buffer fix addressed).
+        let mut buffer = vec![b3.clone()];
+        assert_eq!(BlockQueue::commit_ready(&mut buffer, &mut bc), 0);
+        assert_eq!(bc.chain.len(), 1);
+        assert_eq!(buffer.len(), 1);
+
+        // Once b2 arrives both blocks commit contiguously.
+        buffer.push(b2.clone());
+        assert_eq!(BlockQueue::commit_ready(&mut buffer, &mut bc), 2);
+        assert_eq!(bc.chain.len(), 3);
+        assert!(buffer.is_empty());
+    }
 }